@@ -31,6 +31,55 @@ const NAME_SYSTEM_PAGES: &str = "system_pages";
 const STAT_FREEMEM: &str = "freemem";
 const STAT_PHYSMEM: &str = "physmem";
 
+const STAT_AVENRUN_1MIN: &str = "avenrun_1min";
+const STAT_AVENRUN_5MIN: &str = "avenrun_5min";
+const STAT_AVENRUN_15MIN: &str = "avenrun_15min";
+
+/// Fixed-point scale factor used by the kernel for the avenrun_* load
+/// average statistics; see FSCALE in <sys/param.h>.
+const FSCALE: f64 = 256.0;
+
+const CLASS_NET: &str = "net";
+const STAT_RBYTES64: &str = "rbytes64";
+const STAT_OBYTES64: &str = "obytes64";
+const STAT_IPACKETS64: &str = "ipackets64";
+const STAT_OPACKETS64: &str = "opackets64";
+const STAT_IERRORS: &str = "ierrors";
+const STAT_OERRORS: &str = "oerrors";
+
+/// Legacy (pre-gldv3) 32-bit counter names, used as a fallback when the
+/// 64-bit named statistics are not present on a given driver.
+const STAT_RBYTES: &str = "rbytes";
+const STAT_OBYTES: &str = "obytes";
+const STAT_IPACKETS: &str = "ipackets";
+const STAT_OPACKETS: &str = "opackets";
+
+const CLASS_DISK: &str = "disk";
+
+/// kstat_type(3KSTAT) value for a kstat_io_t, as opposed to the more
+/// common KSTAT_TYPE_NAMED.
+const KSTAT_TYPE_IO: c_uchar = 3;
+
+/// KSTAT_DATA_* discriminants for kstat_named_t's "data_type" field, from
+/// <sys/kstat.h>.
+const KSTAT_DATA_CHAR: c_uchar = 0;
+const KSTAT_DATA_INT32: c_uchar = 1;
+const KSTAT_DATA_UINT32: c_uchar = 2;
+const KSTAT_DATA_INT64: c_uchar = 3;
+const KSTAT_DATA_UINT64: c_uchar = 4;
+const KSTAT_DATA_STRING: c_uchar = 9;
+
+/// A type-safe decoding of a kstat_named_t value, selected by its
+/// "data_type" field rather than assumed by the caller.
+pub enum KstatData {
+    Char([u8; 16]),
+    Int32(i32),
+    UInt32(u32),
+    Int64(i64),
+    UInt64(u64),
+    String(String),
+}
+
 
 #[repr(C)]
 struct Kstat {
@@ -58,6 +107,10 @@ impl Kstat {
     unsafe fn module(&self) -> String {
         CStr::from_ptr(self.ks_module.as_ptr()).to_str().unwrap().to_string()
     }
+
+    unsafe fn class(&self) -> String {
+        CStr::from_ptr(self.ks_class.as_ptr()).to_str().unwrap().to_string()
+    }
 }
 
 #[repr(C)]
@@ -67,6 +120,15 @@ struct KstatCtl {
     kc_kd: c_int,
 }
 
+/// The layout of the "str" arm of kstat_named_t's value union: a pointer to
+/// the string data, plus its length, used for KSTAT_DATA_STRING values.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct KstatNamedStr {
+    ptr: *const c_char,
+    len: u32,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 union KstatValue {
@@ -74,6 +136,8 @@ union KstatValue {
     l: c_long,
     ul: c_ulong,
     ui32: u32,
+    ui64: u64,
+    str_: KstatNamedStr,
 }
 
 #[repr(C)]
@@ -83,6 +147,24 @@ struct KstatNamed {
     value: KstatValue,
 }
 
+/// Mirrors kstat_io_t from <sys/kstat.h>, as filled in by kstat_read(3KSTAT)
+/// for a KSTAT_TYPE_IO kstat (e.g. a "disk" class kstat).
+#[repr(C)]
+struct KstatIo {
+    nread: u64,
+    nwritten: u64,
+    reads: u32,
+    writes: u32,
+    wtime: c_longlong,
+    wlentime: c_longlong,
+    wlastupdate: c_longlong,
+    rtime: c_longlong,
+    rlentime: c_longlong,
+    rlastupdate: c_longlong,
+    wcnt: u32,
+    rcnt: u32,
+}
+
 extern "C" {
     fn kstat_open() -> *mut KstatCtl;
     fn kstat_close(kc: *mut KstatCtl) -> c_int;
@@ -91,6 +173,22 @@ extern "C" {
     fn kstat_read(kc: *mut KstatCtl, ksp: *mut Kstat, buf: *mut c_void)
         -> c_int;
     fn kstat_data_lookup(ksp: *mut Kstat, name: *const c_char) -> *mut c_void;
+    fn kstat_chain_update(kc: *mut KstatCtl) -> c_int;
+}
+
+/// errno(3C) value for EAGAIN, as returned by kstat_chain_update(3KSTAT)
+/// while the kernel's kstat chain is in the middle of being updated.
+const EAGAIN: i32 = 11;
+
+/// Outcome of a call to KstatWrapper::refresh().
+pub enum ChainUpdate {
+    /// The chain is unchanged; any previously obtained kstat pointers
+    /// remain valid.
+    Unchanged,
+    /// The chain changed; any cached kstat pointers must be re-looked-up.
+    Changed,
+    /// The chain is being updated right now; try again shortly.
+    Retry,
 }
 
 /// Minimal wrapper around libkstat(3LIB) on illumos and Solaris systems.
@@ -139,6 +237,30 @@ impl KstatWrapper {
         self.stepping = false;
     }
 
+    /// Call kstat_chain_update(3KSTAT) to bring the chain up to date.  Long-
+    /// lived callers that hold a KstatWrapper open across many samples
+    /// should call this before each round of lookups.
+    fn refresh(&mut self) -> Result<ChainUpdate> {
+        let rc = unsafe { kstat_chain_update(self.kc) };
+
+        if rc == -1 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(EAGAIN) {
+                return Ok(ChainUpdate::Retry);
+            }
+            return Err(format!("kstat_chain_update(3KSTAT) failed: {}", err)
+                .into());
+        }
+
+        if rc > 0 {
+            self.ksp = null_mut();
+            self.stepping = false;
+            return Ok(ChainUpdate::Changed);
+        }
+
+        Ok(ChainUpdate::Unchanged)
+    }
+
     /// Call once to start iterating, and then repeatedly for each additional
     /// kstat in the chain.  Returns false once there are no more kstat entries.
     fn step(&mut self) -> bool {
@@ -174,6 +296,15 @@ impl KstatWrapper {
         }
     }
 
+    /// Return the class of the current kstat.
+    fn class(&self) -> Option<String> {
+        if self.ksp == null_mut() {
+            None
+        } else {
+            Some(unsafe { (*self.ksp).class() })
+        }
+    }
+
     /// Look up a named kstat value.  For internal use by typed accessors.
     fn data_value(&self, statistic: &str) -> Option<*const KstatNamed> {
         if self.ksp == null_mut() {
@@ -196,29 +327,94 @@ impl KstatWrapper {
         }
     }
 
+    /// Look up a named kstat value and decode it according to its actual
+    /// "data_type", rather than assuming the caller's expected shape.
+    fn data_typed(&self, statistic: &str) -> Option<KstatData> {
+        let knp = self.data_value(statistic)?;
+
+        unsafe {
+            match (*knp).data_type {
+                KSTAT_DATA_CHAR => {
+                    let mut out = [0u8; 16];
+                    for (i, b) in (*knp).value.c.iter().enumerate() {
+                        out[i] = *b as u8;
+                    }
+                    Some(KstatData::Char(out))
+                }
+                KSTAT_DATA_INT32 => Some(KstatData::Int32((*knp).value.ui32 as i32)),
+                KSTAT_DATA_UINT32 => Some(KstatData::UInt32((*knp).value.ui32)),
+                KSTAT_DATA_INT64 => Some(KstatData::Int64((*knp).value.ui64 as i64)),
+                KSTAT_DATA_UINT64 => Some(KstatData::UInt64((*knp).value.ui64)),
+                KSTAT_DATA_STRING => {
+                    let s = (*knp).value.str_;
+                    if s.ptr == null() {
+                        Some(KstatData::String(String::new()))
+                    } else {
+                        let bytes = std::slice::from_raw_parts(
+                            s.ptr as *const u8, s.len as usize);
+                        Some(KstatData::String(
+                            String::from_utf8_lossy(bytes).into_owned()))
+                    }
+                }
+                _ => None,
+            }
+        }
+    }
+
     /// Look up a named kstat value and interpret it as a "long_t".
     fn data_long(&self, statistic: &str) -> Option<i64> {
-        match self.data_value(statistic) {
-            Some(knp) => unsafe { Some((*knp).value.l) },
-            None => None,
+        match self.data_typed(statistic) {
+            Some(KstatData::Int32(v)) => Some(v as i64),
+            Some(KstatData::Int64(v)) => Some(v),
+            _ => None,
         }
     }
 
     /// Look up a named kstat value and interpret it as a "ulong_t".
     fn data_ulong(&self, statistic: &str) -> Option<u64> {
-        match self.data_value(statistic) {
-            Some(knp) => unsafe { Some((*knp).value.ul) },
-            None => None,
+        match self.data_typed(statistic) {
+            Some(KstatData::UInt32(v)) => Some(v as u64),
+            Some(KstatData::UInt64(v)) => Some(v),
+            _ => None,
         }
     }
 
     /// Look up a named kstat value and interpret it as a "uint32_t".
     fn data_u32(&self, statistic: &str) -> Option<u32> {
-        match self.data_value(statistic) {
-            Some(knp) => unsafe { Some((*knp).value.ui32) },
-            None => None,
+        match self.data_typed(statistic) {
+            Some(KstatData::UInt32(v)) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Look up a named kstat value and interpret it as a "uint64_t".
+    fn data_u64(&self, statistic: &str) -> Option<u64> {
+        match self.data_typed(statistic) {
+            Some(KstatData::UInt64(v)) => Some(v),
+            _ => None,
         }
     }
+
+    /// Read the current kstat as a kstat_io_t, for a KSTAT_TYPE_IO kstat.
+    fn read_io(&self) -> Option<KstatIo> {
+        if self.ksp == null_mut() {
+            return None;
+        }
+
+        if unsafe { (*self.ksp).ks_type } != KSTAT_TYPE_IO {
+            return None;
+        }
+
+        let mut io = std::mem::MaybeUninit::<KstatIo>::uninit();
+
+        if unsafe {
+            kstat_read(self.kc, self.ksp, io.as_mut_ptr() as *mut c_void)
+        } == -1 {
+            return None;
+        }
+
+        Some(unsafe { io.assume_init() })
+    }
 }
 
 impl Drop for KstatWrapper {
@@ -227,87 +423,242 @@ impl Drop for KstatWrapper {
     }
 }
 
-pub fn cpu_mhz() -> Result<u64> {
-    let mut k = KstatWrapper::open()?;
+pub struct Pages {
+    pub freemem: u64,
+    pub physmem: u64,
+}
+
+pub struct LoadAvg {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
+}
+
+pub struct NetworkStats {
+    pub name: String,
+    pub rbytes: u64,
+    pub obytes: u64,
+    pub ipackets: u64,
+    pub opackets: u64,
+    pub ierrors: u32,
+    pub oerrors: u32,
+}
+
+pub struct DiskIo {
+    pub name: String,
+    pub nread: u64,
+    pub nwritten: u64,
+    pub reads: u32,
+    pub writes: u32,
+}
+
+/// A persistent handle onto an open kstat_open(3KSTAT) control structure.
+///
+/// Unlike the free functions in this module, which open and close a fresh
+/// handle on every call, a `KstatHandle` can be kept around by a caller
+/// that wants to sample repeatedly (e.g. a monitoring loop), and only
+/// needs to call `refresh()` between rounds rather than reopening.
+pub struct KstatHandle {
+    k: KstatWrapper,
+}
+
+impl KstatHandle {
+    /// Open a new persistent handle onto the kstat chain.
+    pub fn open() -> Result<Self> {
+        Ok(KstatHandle { k: KstatWrapper::open()? })
+    }
+
+    /// Bring the chain up to date; see kstat_chain_update(3KSTAT).  A
+    /// caller should check for `ChainUpdate::Retry` and poll again after a
+    /// short delay, and should assume any previously read data is stale
+    /// after a `ChainUpdate::Changed`.
+    pub fn refresh(&mut self) -> Result<ChainUpdate> {
+        self.k.refresh()
+    }
+
+    pub fn cpu_mhz(&mut self) -> Result<u64> {
+        self.k.lookup(Some(MODULE_CPU_INFO), None);
+        while self.k.step() {
+            if self.k.module().unwrap() != MODULE_CPU_INFO {
+                continue;
+            }
 
-    k.lookup(Some(MODULE_CPU_INFO), None);
-    while k.step() {
-        if k.module().unwrap() != MODULE_CPU_INFO {
-            continue;
+            if let Some(mhz) = self.k.data_long(STAT_CLOCK_MHZ) {
+                return Ok(mhz as u64);
+            }
         }
 
-        if let Some(mhz) = k.data_long(STAT_CLOCK_MHZ) {
-            return Ok(mhz as u64);
+        return Err("cpu speed kstat not found".into());
+    }
+
+    pub fn boot_time(&mut self) -> Result<u64> {
+        self.k.lookup(Some(MODULE_UNIX), Some(NAME_SYSTEM_MISC));
+        while self.k.step() {
+            if self.k.module().unwrap() != MODULE_UNIX ||
+                self.k.name().unwrap() != NAME_SYSTEM_MISC
+            {
+                continue;
+            }
+
+            if let Some(boot_time) = self.k.data_u32(STAT_BOOT_TIME) {
+                return Ok(boot_time as u64);
+            }
         }
+
+        return Err("boot time kstat not found".into());
     }
 
-    return Err("cpu speed kstat not found".into());
-}
+    pub fn nproc(&mut self) -> Result<u64> {
+        self.k.lookup(Some(MODULE_UNIX), Some(NAME_SYSTEM_MISC));
+        while self.k.step() {
+            if self.k.module().unwrap() != MODULE_UNIX ||
+                self.k.name().unwrap() != NAME_SYSTEM_MISC
+            {
+                continue;
+            }
 
-pub fn boot_time() -> Result<u64> {
-    let mut k = KstatWrapper::open()?;
-
-    k.lookup(Some(MODULE_UNIX), Some(NAME_SYSTEM_MISC));
-    while k.step() {
-        if k.module().unwrap() != MODULE_UNIX ||
-            k.name().unwrap() != NAME_SYSTEM_MISC
-        {
-            continue;
+            if let Some(nproc) = self.k.data_u32(STAT_NPROC) {
+                return Ok(nproc as u64);
+            }
         }
 
-        if let Some(boot_time) = k.data_u32(STAT_BOOT_TIME) {
-            return Ok(boot_time as u64);
+        return Err("process count kstat not found".into());
+    }
+
+    pub fn pages(&mut self) -> Result<Pages> {
+        self.k.lookup(Some(MODULE_UNIX), Some(NAME_SYSTEM_PAGES));
+        while self.k.step() {
+            if self.k.module().unwrap() != MODULE_UNIX ||
+                self.k.name().unwrap() != NAME_SYSTEM_PAGES
+            {
+                continue;
+            }
+
+            let freemem = self.k.data_ulong(STAT_FREEMEM);
+            let physmem = self.k.data_ulong(STAT_PHYSMEM);
+
+            if freemem.is_some() && physmem.is_some() {
+                return Ok(Pages {
+                    freemem: freemem.unwrap(),
+                    physmem: physmem.unwrap(),
+                });
+            }
         }
+
+        return Err("system pages kstat not available".into());
     }
 
-    return Err("boot time kstat not found".into());
-}
+    pub fn loadavg(&mut self) -> Result<LoadAvg> {
+        self.k.lookup(Some(MODULE_UNIX), Some(NAME_SYSTEM_MISC));
+        while self.k.step() {
+            if self.k.module().unwrap() != MODULE_UNIX ||
+                self.k.name().unwrap() != NAME_SYSTEM_MISC
+            {
+                continue;
+            }
 
-pub fn nproc() -> Result<u64> {
-    let mut k = KstatWrapper::open()?;
-
-    k.lookup(Some(MODULE_UNIX), Some(NAME_SYSTEM_MISC));
-    while k.step() {
-        if k.module().unwrap() != MODULE_UNIX ||
-            k.name().unwrap() != NAME_SYSTEM_MISC
-        {
-            continue;
+            let one = self.k.data_ulong(STAT_AVENRUN_1MIN);
+            let five = self.k.data_ulong(STAT_AVENRUN_5MIN);
+            let fifteen = self.k.data_ulong(STAT_AVENRUN_15MIN);
+
+            if one.is_some() && five.is_some() && fifteen.is_some() {
+                return Ok(LoadAvg {
+                    one: one.unwrap() as f64 / FSCALE,
+                    five: five.unwrap() as f64 / FSCALE,
+                    fifteen: fifteen.unwrap() as f64 / FSCALE,
+                });
+            }
+        }
+
+        return Err("load average kstat not available".into());
+    }
+
+    pub fn network_stats(&mut self) -> Result<Vec<NetworkStats>> {
+        let mut out = Vec::new();
+
+        self.k.lookup(None, None);
+        while self.k.step() {
+            if self.k.class().unwrap() != CLASS_NET {
+                continue;
+            }
+
+            let name = self.k.name().unwrap();
+
+            let rbytes = self.k.data_u64(STAT_RBYTES64)
+                .or_else(|| self.k.data_u32(STAT_RBYTES).map(|v| v as u64));
+            let obytes = self.k.data_u64(STAT_OBYTES64)
+                .or_else(|| self.k.data_u32(STAT_OBYTES).map(|v| v as u64));
+            let ipackets = self.k.data_u64(STAT_IPACKETS64)
+                .or_else(|| self.k.data_u32(STAT_IPACKETS).map(|v| v as u64));
+            let opackets = self.k.data_u64(STAT_OPACKETS64)
+                .or_else(|| self.k.data_u32(STAT_OPACKETS).map(|v| v as u64));
+            let ierrors = self.k.data_u32(STAT_IERRORS);
+            let oerrors = self.k.data_u32(STAT_OERRORS);
+
+            out.push(NetworkStats {
+                name: name,
+                rbytes: rbytes.unwrap_or(0),
+                obytes: obytes.unwrap_or(0),
+                ipackets: ipackets.unwrap_or(0),
+                opackets: opackets.unwrap_or(0),
+                ierrors: ierrors.unwrap_or(0),
+                oerrors: oerrors.unwrap_or(0),
+            });
         }
 
-        if let Some(nproc) = k.data_u32(STAT_NPROC) {
-            return Ok(nproc as u64);
+        Ok(out)
+    }
+
+    pub fn disk_io(&mut self) -> Result<Vec<DiskIo>> {
+        let mut out = Vec::new();
+
+        self.k.lookup(None, None);
+        while self.k.step() {
+            if self.k.class().unwrap() != CLASS_DISK {
+                continue;
+            }
+
+            let name = self.k.name().unwrap();
+
+            if let Some(io) = self.k.read_io() {
+                out.push(DiskIo {
+                    name: name,
+                    nread: io.nread,
+                    nwritten: io.nwritten,
+                    reads: io.reads,
+                    writes: io.writes,
+                });
+            }
         }
+
+        Ok(out)
     }
+}
 
-    return Err("process count kstat not found".into());
+pub fn cpu_mhz() -> Result<u64> {
+    KstatHandle::open()?.cpu_mhz()
 }
 
-pub struct Pages {
-    pub freemem: u64,
-    pub physmem: u64,
+pub fn boot_time() -> Result<u64> {
+    KstatHandle::open()?.boot_time()
+}
+
+pub fn nproc() -> Result<u64> {
+    KstatHandle::open()?.nproc()
 }
 
 pub fn pages() -> Result<Pages> {
-    let mut k = KstatWrapper::open()?;
-
-    k.lookup(Some(MODULE_UNIX), Some(NAME_SYSTEM_PAGES));
-    while k.step() {
-        if k.module().unwrap() != MODULE_UNIX ||
-            k.name().unwrap() != NAME_SYSTEM_PAGES
-        {
-            continue;
-        }
+    KstatHandle::open()?.pages()
+}
 
-        let freemem = k.data_ulong(STAT_FREEMEM);
-        let physmem = k.data_ulong(STAT_PHYSMEM);
+pub fn loadavg() -> Result<LoadAvg> {
+    KstatHandle::open()?.loadavg()
+}
 
-        if freemem.is_some() && physmem.is_some() {
-            return Ok(Pages {
-                freemem: freemem.unwrap(),
-                physmem: physmem.unwrap(),
-            });
-        }
-    }
+pub fn network_stats() -> Result<Vec<NetworkStats>> {
+    KstatHandle::open()?.network_stats()
+}
 
-    return Err("system pages kstat not available".into());
+pub fn disk_io() -> Result<Vec<DiskIo>> {
+    KstatHandle::open()?.disk_io()
 }